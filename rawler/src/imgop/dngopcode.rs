@@ -1,7 +1,32 @@
-use byteorder::{BigEndian, ReadBytesExt};
+use crate::imgop::{clip, Dim2};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use num_enum::TryFromPrimitive;
 use std::io::{Cursor, Error, ErrorKind, Result, Seek, SeekFrom};
 
+/// DNG opcode-list spec version written for every encoded opcode (DNG 1.3.0.0).
+const DNG_OPCODE_SPEC_VERSION: u32 = 0x0103_0000;
+
+/// Error for an opcode whose region does not fit into the supplied image.
+fn region_out_of_bounds() -> Error {
+  Error::new(ErrorKind::InvalidData, "Opcode region out of bounds")
+}
+
+/// Error for a declared length that exceeds the bytes left in the opcode.
+fn not_enough_data() -> Error {
+  Error::new(ErrorKind::UnexpectedEof, "Not enough data left in opcode")
+}
+
+/// Validate that `count` elements of `elem_size` bytes fit within the opcode
+/// boundary `limit` before allocating, guarding against integer overflow and
+/// hostile lengths. Returns `count` on success.
+fn bounded_count(cur: &Cursor<&[u8]>, limit: u64, count: usize, elem_size: usize) -> Result<usize> {
+  let bytes = count.checked_mul(elem_size).ok_or_else(not_enough_data)?;
+  if bytes as u64 > limit.saturating_sub(cur.position()) {
+    return Err(not_enough_data());
+  }
+  Ok(count)
+}
+
 #[derive(Copy, Clone, TryFromPrimitive)]
 #[repr(u32)]
 pub enum DngOpcodeId {
@@ -34,6 +59,10 @@ impl DngOpcodeFlags {
       preview_skip: v & 2 > 0,
     }
   }
+
+  fn encode(&self) -> u32 {
+    (self.optional as u32) | ((self.preview_skip as u32) << 1)
+  }
 }
 
 #[derive(Debug)]
@@ -61,6 +90,38 @@ impl DngOpcodeRegion {
       col_pitch: cur.read_u32::<BigEndian>()?,
     })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    cur.write_u32::<BigEndian>(self.top)?;
+    cur.write_u32::<BigEndian>(self.left)?;
+    cur.write_u32::<BigEndian>(self.bottom)?;
+    cur.write_u32::<BigEndian>(self.right)?;
+    cur.write_u32::<BigEndian>(self.plane)?;
+    cur.write_u32::<BigEndian>(self.planes)?;
+    cur.write_u32::<BigEndian>(self.row_pitch)?;
+    cur.write_u32::<BigEndian>(self.col_pitch)?;
+    Ok(())
+  }
+
+  /// Resolve the region against an image of dimension `dim` with `plane_count` planes.
+  ///
+  /// Returns `(top, left, bottom, right, plane, planes, row_pitch, col_pitch)` in
+  /// sample coordinates, with a zero pitch raised to 1, or an error if the region
+  /// lies outside the image.
+  fn resolve(&self, dim: Dim2, plane_count: usize) -> Result<(usize, usize, usize, usize, usize, usize, usize, usize)> {
+    let top = self.top as usize;
+    let left = self.left as usize;
+    let bottom = self.bottom as usize;
+    let right = self.right as usize;
+    let plane = self.plane as usize;
+    let planes = self.planes as usize;
+    if bottom > dim.h || right > dim.w || top > bottom || left > right || plane + planes > plane_count {
+      return Err(region_out_of_bounds());
+    }
+    let row_pitch = (self.row_pitch as usize).max(1);
+    let col_pitch = (self.col_pitch as usize).max(1);
+    Ok((top, left, bottom, right, plane, planes, row_pitch, col_pitch))
+  }
 }
 
 #[derive(Debug)]
@@ -80,6 +141,12 @@ impl WarpRectilinearCoef {
       kt: kt.try_into().unwrap(),
     })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    self.kr.iter().try_for_each(|v| cur.write_f64::<BigEndian>(*v))?;
+    self.kt.iter().try_for_each(|v| cur.write_f64::<BigEndian>(*v))?;
+    Ok(())
+  }
 }
 
 #[derive(Debug)]
@@ -91,8 +158,9 @@ pub struct WarpRectilinear {
 }
 
 impl WarpRectilinear {
-  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>) -> Result<WarpRectilinear> {
+  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>, limit: u64) -> Result<WarpRectilinear> {
     let n = cur.read_u32::<BigEndian>()? as usize;
+    bounded_count(cur, limit, n, 6 * 8)?;
     let coefs = (0..n).map(|_| WarpRectilinearCoef::decode(cur)).collect::<Result<Vec<WarpRectilinearCoef>>>()?;
     let center_x = cur.read_f64::<BigEndian>()?;
     let center_y = cur.read_f64::<BigEndian>()?;
@@ -103,6 +171,30 @@ impl WarpRectilinear {
       center_y,
     })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    cur.write_u32::<BigEndian>(self.coefs.len() as u32)?;
+    self.coefs.iter().try_for_each(|c| c.encode(cur))?;
+    cur.write_f64::<BigEndian>(self.center_x)?;
+    cur.write_f64::<BigEndian>(self.center_y)?;
+    Ok(())
+  }
+
+  /// Rectify radial and tangential lens distortion by resampling each plane.
+  fn apply(&self, data: &mut [f32], dim: Dim2, plane_count: usize) -> Result<()> {
+    if self.coefs.is_empty() {
+      return Err(Error::new(ErrorKind::InvalidData, "WarpRectilinear without coefficients"));
+    }
+    let coefs = &self.coefs;
+    warp_resample(data, dim, plane_count, self.center_x, self.center_y, |plane, dx, dy| {
+      let c = &coefs[if coefs.len() == 1 { 0 } else { plane.min(coefs.len() - 1) }];
+      let r2 = dx * dx + dy * dy;
+      let f = c.kr[0] + c.kr[1] * r2 + c.kr[2] * r2 * r2 + c.kr[3] * r2 * r2 * r2;
+      let dx_t = c.kt[0] * (r2 + 2.0 * dx * dx) + 2.0 * c.kt[1] * dx * dy;
+      let dy_t = c.kt[1] * (r2 + 2.0 * dy * dy) + 2.0 * c.kt[0] * dx * dy;
+      (dx * f + dx_t, dy * f + dy_t)
+    })
+  }
 }
 
 #[derive(Debug)]
@@ -116,6 +208,11 @@ impl WarpFisheyeCoef {
     cur.read_f64_into::<BigEndian>(&mut kr)?;
     Ok(WarpFisheyeCoef { kr: kr.try_into().unwrap() })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    self.kr.iter().try_for_each(|v| cur.write_f64::<BigEndian>(*v))?;
+    Ok(())
+  }
 }
 
 #[derive(Debug)]
@@ -127,8 +224,9 @@ pub struct WarpFisheye {
 }
 
 impl WarpFisheye {
-  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>) -> Result<WarpFisheye> {
+  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>, limit: u64) -> Result<WarpFisheye> {
     let n = cur.read_u32::<BigEndian>()? as usize;
+    bounded_count(cur, limit, n, 4 * 8)?;
     let coefs = (0..n).map(|_| WarpFisheyeCoef::decode(cur)).collect::<Result<Vec<WarpFisheyeCoef>>>()?;
     let center_x = cur.read_f64::<BigEndian>()?;
     let center_y = cur.read_f64::<BigEndian>()?;
@@ -139,6 +237,30 @@ impl WarpFisheye {
       center_y,
     })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    cur.write_u32::<BigEndian>(self.coefs.len() as u32)?;
+    self.coefs.iter().try_for_each(|c| c.encode(cur))?;
+    cur.write_f64::<BigEndian>(self.center_x)?;
+    cur.write_f64::<BigEndian>(self.center_y)?;
+    Ok(())
+  }
+
+  /// Rectify fisheye distortion using the angle-based model by resampling each plane.
+  fn apply(&self, data: &mut [f32], dim: Dim2, plane_count: usize) -> Result<()> {
+    if self.coefs.is_empty() {
+      return Err(Error::new(ErrorKind::InvalidData, "WarpFisheye without coefficients"));
+    }
+    let coefs = &self.coefs;
+    warp_resample(data, dim, plane_count, self.center_x, self.center_y, |plane, dx, dy| {
+      let c = &coefs[if coefs.len() == 1 { 0 } else { plane.min(coefs.len() - 1) }];
+      let r = (dx * dx + dy * dy).sqrt();
+      let theta = r.atan();
+      let poly = c.kr[0] + c.kr[1] * theta.powi(2) + c.kr[2] * theta.powi(4) + c.kr[3] * theta.powi(6);
+      let f = if r > 0.0 { theta * poly / r } else { poly };
+      (dx * f, dy * f)
+    })
+  }
 }
 
 #[derive(Debug)]
@@ -150,7 +272,7 @@ pub struct FixVignetteRadial {
 }
 
 impl FixVignetteRadial {
-  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>) -> Result<FixVignetteRadial> {
+  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>, _limit: u64) -> Result<FixVignetteRadial> {
     let mut k = vec![0.0; 5];
     cur.read_f64_into::<BigEndian>(&mut k)?;
     let center_x = cur.read_f64::<BigEndian>()?;
@@ -162,6 +284,13 @@ impl FixVignetteRadial {
       center_y,
     })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    self.k.iter().try_for_each(|v| cur.write_f64::<BigEndian>(*v))?;
+    cur.write_f64::<BigEndian>(self.center_x)?;
+    cur.write_f64::<BigEndian>(self.center_y)?;
+    Ok(())
+  }
 }
 
 #[derive(Debug)]
@@ -172,13 +301,32 @@ pub struct FixBadPixelsConstant {
 }
 
 impl FixBadPixelsConstant {
-  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>) -> Result<FixBadPixelsConstant> {
+  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>, _limit: u64) -> Result<FixBadPixelsConstant> {
     Ok(FixBadPixelsConstant {
       flags,
       constant: cur.read_u32::<BigEndian>()?,
       bayer_phase: cur.read_u32::<BigEndian>()?,
     })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    cur.write_u32::<BigEndian>(self.constant)?;
+    cur.write_u32::<BigEndian>(self.bayer_phase)?;
+    Ok(())
+  }
+
+  /// Conceal every sample equal to `constant`, treating each as an isolated defect.
+  fn apply(&self, data: &mut [f32], dim: Dim2, plane_count: usize) -> Result<()> {
+    let target = self.constant as f32;
+    let defects: Vec<(usize, usize)> = (0..dim.h)
+      .flat_map(|row| (0..dim.w).map(move |col| (row, col)))
+      .filter(|&(row, col)| data[(row * dim.w + col) * plane_count] == target)
+      .collect();
+    for (row, col) in defects {
+      conceal_point(data, dim, plane_count, row, col, self.bayer_phase);
+    }
+    Ok(())
+  }
 }
 
 #[derive(Debug)]
@@ -194,6 +342,12 @@ impl BadPoint {
       column: cur.read_u32::<BigEndian>()?,
     })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    cur.write_u32::<BigEndian>(self.row)?;
+    cur.write_u32::<BigEndian>(self.column)?;
+    Ok(())
+  }
 }
 
 #[derive(Debug)]
@@ -213,6 +367,14 @@ impl BadRect {
       right: cur.read_u32::<BigEndian>()?,
     })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    cur.write_u32::<BigEndian>(self.top)?;
+    cur.write_u32::<BigEndian>(self.left)?;
+    cur.write_u32::<BigEndian>(self.bottom)?;
+    cur.write_u32::<BigEndian>(self.right)?;
+    Ok(())
+  }
 }
 
 #[derive(Debug)]
@@ -224,11 +386,13 @@ pub struct FixBadPixelsList {
 }
 
 impl FixBadPixelsList {
-  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>) -> Result<FixBadPixelsList> {
+  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>, limit: u64) -> Result<FixBadPixelsList> {
     let bayer_phase = cur.read_u32::<BigEndian>()?;
-    let num_points = cur.read_u32::<BigEndian>()?;
-    let num_rects = cur.read_u32::<BigEndian>()?;
+    let num_points = cur.read_u32::<BigEndian>()? as usize;
+    let num_rects = cur.read_u32::<BigEndian>()? as usize;
+    bounded_count(cur, limit, num_points, 2 * 4)?;
     let bad_points = (0..num_points).map(|_| BadPoint::decode(cur)).collect::<Result<Vec<BadPoint>>>()?;
+    bounded_count(cur, limit, num_rects, 4 * 4)?;
     let bad_rects = (0..num_rects).map(|_| BadRect::decode(cur)).collect::<Result<Vec<BadRect>>>()?;
     Ok(FixBadPixelsList {
       flags,
@@ -237,6 +401,38 @@ impl FixBadPixelsList {
       bad_rects,
     })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    cur.write_u32::<BigEndian>(self.bayer_phase)?;
+    cur.write_u32::<BigEndian>(self.bad_points.len() as u32)?;
+    cur.write_u32::<BigEndian>(self.bad_rects.len() as u32)?;
+    self.bad_points.iter().try_for_each(|p| p.encode(cur))?;
+    self.bad_rects.iter().try_for_each(|r| r.encode(cur))?;
+    Ok(())
+  }
+
+  /// Conceal the explicit bad points and bad rectangles.
+  fn apply(&self, data: &mut [f32], dim: Dim2, plane_count: usize) -> Result<()> {
+    // Validate every point and rectangle before concealing any of them, so a
+    // skipped optional opcode never leaves the buffer partially modified.
+    for point in &self.bad_points {
+      if point.row as usize >= dim.h || point.column as usize >= dim.w {
+        return Err(region_out_of_bounds());
+      }
+    }
+    for rect in &self.bad_rects {
+      if rect.bottom as usize > dim.h || rect.right as usize > dim.w || rect.top > rect.bottom || rect.left > rect.right {
+        return Err(region_out_of_bounds());
+      }
+    }
+    for point in &self.bad_points {
+      conceal_point(data, dim, plane_count, point.row as usize, point.column as usize, self.bayer_phase);
+    }
+    for rect in &self.bad_rects {
+      conceal_rect(data, dim, plane_count, rect);
+    }
+    Ok(())
+  }
 }
 
 #[derive(Debug)]
@@ -249,7 +445,7 @@ pub struct TrimBounds {
 }
 
 impl TrimBounds {
-  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>) -> Result<TrimBounds> {
+  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>, _limit: u64) -> Result<TrimBounds> {
     Ok(TrimBounds {
       flags,
       top: cur.read_u32::<BigEndian>()?,
@@ -258,6 +454,14 @@ impl TrimBounds {
       right: cur.read_u32::<BigEndian>()?,
     })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    cur.write_u32::<BigEndian>(self.top)?;
+    cur.write_u32::<BigEndian>(self.left)?;
+    cur.write_u32::<BigEndian>(self.bottom)?;
+    cur.write_u32::<BigEndian>(self.right)?;
+    Ok(())
+  }
 }
 
 #[derive(Debug)]
@@ -268,13 +472,41 @@ pub struct MapTable {
 }
 
 impl MapTable {
-  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>) -> Result<MapTable> {
+  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>, limit: u64) -> Result<MapTable> {
     let region = DngOpcodeRegion::decode(cur)?;
     let len: usize = cur.read_u32::<BigEndian>()? as usize;
+    bounded_count(cur, limit, len, 2)?;
     let mut table = vec![0; len];
     cur.read_u16_into::<BigEndian>(&mut table)?;
     Ok(MapTable { flags, region, table })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    self.region.encode(cur)?;
+    cur.write_u32::<BigEndian>(self.table.len() as u32)?;
+    self.table.iter().try_for_each(|v| cur.write_u16::<BigEndian>(*v))?;
+    Ok(())
+  }
+
+  /// Map every pixel in the region through the `u16` lookup table.
+  fn apply(&self, data: &mut [f32], dim: Dim2, plane_count: usize) -> Result<()> {
+    if self.table.is_empty() {
+      return Err(Error::new(ErrorKind::InvalidData, "Empty opcode map table"));
+    }
+    let (top, left, bottom, right, plane, planes, row_pitch, col_pitch) = self.region.resolve(dim, plane_count)?;
+    let last = (self.table.len() - 1) as f32;
+    for p in plane..plane + planes {
+      for y in (top..bottom).step_by(row_pitch) {
+        for x in (left..right).step_by(col_pitch) {
+          let idx = (y * dim.w + x) * plane_count + p;
+          let pos = clip(data[idx], 0.0, 1.0) * last;
+          let value = self.table[pos.round() as usize];
+          data[idx] = value as f32 / u16::MAX as f32;
+        }
+      }
+    }
+    Ok(())
+  }
 }
 
 #[derive(Debug)]
@@ -285,13 +517,38 @@ pub struct MapPolynomial {
 }
 
 impl MapPolynomial {
-  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>) -> Result<MapPolynomial> {
+  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>, limit: u64) -> Result<MapPolynomial> {
     let region = DngOpcodeRegion::decode(cur)?;
     let degree: usize = cur.read_u32::<BigEndian>()? as usize;
-    let mut coefs = vec![0.0; degree + 1];
+    let len = degree.checked_add(1).ok_or_else(not_enough_data)?;
+    bounded_count(cur, limit, len, 8)?;
+    let mut coefs = vec![0.0; len];
     cur.read_f64_into::<BigEndian>(&mut coefs)?;
     Ok(MapPolynomial { flags, region, coefs })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    self.region.encode(cur)?;
+    cur.write_u32::<BigEndian>((self.coefs.len() - 1) as u32)?;
+    self.coefs.iter().try_for_each(|v| cur.write_f64::<BigEndian>(*v))?;
+    Ok(())
+  }
+
+  /// Evaluate the polynomial `sum_i coefs[i] * in^i` for every pixel in the region.
+  fn apply(&self, data: &mut [f32], dim: Dim2, plane_count: usize) -> Result<()> {
+    let (top, left, bottom, right, plane, planes, row_pitch, col_pitch) = self.region.resolve(dim, plane_count)?;
+    for p in plane..plane + planes {
+      for y in (top..bottom).step_by(row_pitch) {
+        for x in (left..right).step_by(col_pitch) {
+          let idx = (y * dim.w + x) * plane_count + p;
+          let v = data[idx] as f64;
+          let out = self.coefs.iter().rev().fold(0.0, |acc, c| acc * v + c);
+          data[idx] = clip(out as f32, 0.0, 1.0);
+        }
+      }
+    }
+    Ok(())
+  }
 }
 
 #[derive(Debug)]
@@ -309,7 +566,7 @@ pub struct GainMap {
 }
 
 impl GainMap {
-  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>) -> Result<GainMap> {
+  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>, limit: u64) -> Result<GainMap> {
     let region = DngOpcodeRegion::decode(cur)?;
     let map_points_v = cur.read_u32::<BigEndian>()?;
     let map_points_h = cur.read_u32::<BigEndian>()?;
@@ -318,7 +575,11 @@ impl GainMap {
     let map_origin_v = cur.read_f64::<BigEndian>()?;
     let map_origin_h = cur.read_f64::<BigEndian>()?;
     let map_planes = cur.read_u32::<BigEndian>()?;
-    let len = (map_points_h * map_points_v * map_planes) as usize;
+    let len = (map_points_h as usize)
+      .checked_mul(map_points_v as usize)
+      .and_then(|v| v.checked_mul(map_planes as usize))
+      .ok_or_else(not_enough_data)?;
+    bounded_count(cur, limit, len, 4)?;
     let mut map_gain = vec![0.0; len];
     cur.read_f32_into::<BigEndian>(&mut map_gain)?;
     Ok(GainMap {
@@ -334,6 +595,54 @@ impl GainMap {
       map_gain,
     })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    self.region.encode(cur)?;
+    cur.write_u32::<BigEndian>(self.map_points_v)?;
+    cur.write_u32::<BigEndian>(self.map_points_h)?;
+    cur.write_f64::<BigEndian>(self.map_spacing_v)?;
+    cur.write_f64::<BigEndian>(self.map_spacing_h)?;
+    cur.write_f64::<BigEndian>(self.map_origin_v)?;
+    cur.write_f64::<BigEndian>(self.map_origin_h)?;
+    cur.write_u32::<BigEndian>(self.map_planes)?;
+    self.map_gain.iter().try_for_each(|v| cur.write_f32::<BigEndian>(*v))?;
+    Ok(())
+  }
+
+  /// Bilinearly sample the gain grid and multiply every pixel in the region.
+  fn apply(&self, data: &mut [f32], dim: Dim2, plane_count: usize) -> Result<()> {
+    let (top, left, bottom, right, plane, planes, row_pitch, col_pitch) = self.region.resolve(dim, plane_count)?;
+    let points_h = self.map_points_h as usize;
+    let points_v = self.map_points_v as usize;
+    let map_planes = self.map_planes as usize;
+    if points_h == 0 || points_v == 0 || map_planes == 0 || self.map_gain.len() < points_h * points_v * map_planes {
+      return Err(Error::new(ErrorKind::InvalidData, "Invalid gain map geometry"));
+    }
+    for p in plane..plane + planes {
+      let plane_index = if map_planes == 1 { 0 } else { (p - plane).min(map_planes - 1) };
+      for y in (top..bottom).step_by(row_pitch) {
+        let v = y as f64 / dim.h as f64;
+        let gy = ((v - self.map_origin_v) / self.map_spacing_v).clamp(0.0, (points_v - 1) as f64);
+        let y0 = gy.floor() as usize;
+        let y1 = (y0 + 1).min(points_v - 1);
+        let fy = gy - y0 as f64;
+        for x in (left..right).step_by(col_pitch) {
+          let u = x as f64 / dim.w as f64;
+          let gx = ((u - self.map_origin_h) / self.map_spacing_h).clamp(0.0, (points_h - 1) as f64);
+          let x0 = gx.floor() as usize;
+          let x1 = (x0 + 1).min(points_h - 1);
+          let fx = gx - x0 as f64;
+          let g = |gy: usize, gx: usize| self.map_gain[(gy * points_h + gx) * map_planes + plane_index] as f64;
+          let top_gain = g(y0, x0) * (1.0 - fx) + g(y0, x1) * fx;
+          let bottom_gain = g(y1, x0) * (1.0 - fx) + g(y1, x1) * fx;
+          let gain = top_gain * (1.0 - fy) + bottom_gain * fy;
+          let idx = (y * dim.w + x) * plane_count + p;
+          data[idx] *= gain as f32;
+        }
+      }
+    }
+    Ok(())
+  }
 }
 
 #[derive(Debug)]
@@ -353,6 +662,12 @@ impl WarpRectilinear2Coef {
       kt: kt.try_into().unwrap(),
     })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    self.kr.iter().try_for_each(|v| cur.write_f64::<BigEndian>(*v))?;
+    self.kt.iter().try_for_each(|v| cur.write_f64::<BigEndian>(*v))?;
+    Ok(())
+  }
 }
 
 #[derive(Debug)]
@@ -365,8 +680,9 @@ pub struct WarpRectilinear2 {
 }
 
 impl WarpRectilinear2 {
-  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>) -> Result<WarpRectilinear2> {
+  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>, limit: u64) -> Result<WarpRectilinear2> {
     let n = cur.read_u32::<BigEndian>()? as usize;
+    bounded_count(cur, limit, n, 17 * 8)?;
     let coefs = (0..n)
       .map(|_| WarpRectilinear2Coef::decode(cur))
       .collect::<Result<Vec<WarpRectilinear2Coef>>>()?;
@@ -381,6 +697,39 @@ impl WarpRectilinear2 {
       reciprocal_radial,
     })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    cur.write_u32::<BigEndian>(self.coefs.len() as u32)?;
+    self.coefs.iter().try_for_each(|c| c.encode(cur))?;
+    cur.write_f64::<BigEndian>(self.center_x)?;
+    cur.write_f64::<BigEndian>(self.center_y)?;
+    cur.write_u32::<BigEndian>(self.reciprocal_radial)?;
+    Ok(())
+  }
+
+  /// Rectify lens distortion using the 15-term radial polynomial by resampling each plane.
+  fn apply(&self, data: &mut [f32], dim: Dim2, plane_count: usize) -> Result<()> {
+    if self.coefs.is_empty() {
+      return Err(Error::new(ErrorKind::InvalidData, "WarpRectilinear2 without coefficients"));
+    }
+    let coefs = &self.coefs;
+    let reciprocal = self.reciprocal_radial != 0;
+    warp_resample(data, dim, plane_count, self.center_x, self.center_y, |plane, dx, dy| {
+      let c = &coefs[if coefs.len() == 1 { 0 } else { plane.min(coefs.len() - 1) }];
+      let r2 = dx * dx + dy * dy;
+      // The radial polynomial uses only even powers of the radius, so it is
+      // evaluated over r2 (matching the WarpRectilinear v1 model above).
+      let poly = c.kr.iter().rev().fold(0.0, |acc, k| acc * r2 + k);
+      let f = if reciprocal {
+        if poly != 0.0 { 1.0 / poly } else { 0.0 }
+      } else {
+        poly
+      };
+      let dx_t = c.kt[0] * (r2 + 2.0 * dx * dx) + 2.0 * c.kt[1] * dx * dy;
+      let dy_t = c.kt[1] * (r2 + 2.0 * dy * dy) + 2.0 * c.kt[0] * dx * dy;
+      (dx * f + dx_t, dy * f + dy_t)
+    })
+  }
 }
 
 #[derive(Debug)]
@@ -391,13 +740,54 @@ pub struct ValuesPerRowOrCol {
 }
 
 impl ValuesPerRowOrCol {
-  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>) -> Result<ValuesPerRowOrCol> {
+  fn decode(flags: DngOpcodeFlags, cur: &mut Cursor<&[u8]>, limit: u64) -> Result<ValuesPerRowOrCol> {
     let region = DngOpcodeRegion::decode(cur)?;
     let len: usize = cur.read_u32::<BigEndian>()? as usize;
+    bounded_count(cur, limit, len, 4)?;
     let mut values = vec![0.0; len];
     cur.read_f32_into::<BigEndian>(&mut values)?;
     Ok(ValuesPerRowOrCol { flags, region, values })
   }
+
+  fn encode(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    self.region.encode(cur)?;
+    cur.write_u32::<BigEndian>(self.values.len() as u32)?;
+    self.values.iter().try_for_each(|v| cur.write_f32::<BigEndian>(*v))?;
+    Ok(())
+  }
+
+  /// Apply a per-row or per-column delta/scale to every pixel in the region.
+  ///
+  /// `per_column` selects whether `values` is indexed by column (otherwise row),
+  /// and `scale` selects multiplication over addition.
+  fn apply(&self, data: &mut [f32], dim: Dim2, plane_count: usize, per_column: bool, scale: bool) -> Result<()> {
+    let (top, left, bottom, right, plane, planes, row_pitch, col_pitch) = self.region.resolve(dim, plane_count)?;
+    // Validate the value count before touching any pixel, so a skipped optional
+    // opcode never leaves the buffer partially modified.
+    let needed = if per_column {
+      (right - left).div_ceil(col_pitch)
+    } else {
+      (bottom - top).div_ceil(row_pitch)
+    };
+    if self.values.len() < needed {
+      return Err(region_out_of_bounds());
+    }
+    for p in plane..plane + planes {
+      for y in (top..bottom).step_by(row_pitch) {
+        for x in (left..right).step_by(col_pitch) {
+          let k = if per_column { (x - left) / col_pitch } else { (y - top) / row_pitch };
+          let value = self.values[k];
+          let idx = (y * dim.w + x) * plane_count + p;
+          if scale {
+            data[idx] *= value;
+          } else {
+            data[idx] += value;
+          }
+        }
+      }
+    }
+    Ok(())
+  }
 }
 
 #[derive(Debug)]
@@ -418,6 +808,68 @@ pub enum DngOpcode {
   WarpRectilinear2(WarpRectilinear2),
 }
 
+impl DngOpcode {
+  /// The flags common to every opcode variant.
+  fn flags(&self) -> &DngOpcodeFlags {
+    match self {
+      DngOpcode::WarpRectilinear(op) => &op.flags,
+      DngOpcode::WarpFisheye(op) => &op.flags,
+      DngOpcode::FixVignetteRadial(op) => &op.flags,
+      DngOpcode::FixBadPixelsConstant(op) => &op.flags,
+      DngOpcode::FixBadPixelsList(op) => &op.flags,
+      DngOpcode::TrimBounds(op) => &op.flags,
+      DngOpcode::MapTable(op) => &op.flags,
+      DngOpcode::MapPolynomial(op) => &op.flags,
+      DngOpcode::GainMap(op) => &op.flags,
+      DngOpcode::DeltaPerRow(op) => &op.flags,
+      DngOpcode::DeltaPerColumn(op) => &op.flags,
+      DngOpcode::ScalePerRow(op) => &op.flags,
+      DngOpcode::ScalePerColumn(op) => &op.flags,
+      DngOpcode::WarpRectilinear2(op) => &op.flags,
+    }
+  }
+
+  /// The numeric opcode id for this variant.
+  fn id(&self) -> DngOpcodeId {
+    match self {
+      DngOpcode::WarpRectilinear(_) => DngOpcodeId::WarpRectilinear,
+      DngOpcode::WarpFisheye(_) => DngOpcodeId::WarpFisheye,
+      DngOpcode::FixVignetteRadial(_) => DngOpcodeId::FixVignetteRadial,
+      DngOpcode::FixBadPixelsConstant(_) => DngOpcodeId::FixBadPixelsConstant,
+      DngOpcode::FixBadPixelsList(_) => DngOpcodeId::FixBadPixelsList,
+      DngOpcode::TrimBounds(_) => DngOpcodeId::TrimBounds,
+      DngOpcode::MapTable(_) => DngOpcodeId::MapTable,
+      DngOpcode::MapPolynomial(_) => DngOpcodeId::MapPolynomial,
+      DngOpcode::GainMap(_) => DngOpcodeId::GainMap,
+      DngOpcode::DeltaPerRow(_) => DngOpcodeId::DeltaPerRow,
+      DngOpcode::DeltaPerColumn(_) => DngOpcodeId::DeltaPerColumn,
+      DngOpcode::ScalePerRow(_) => DngOpcodeId::ScalePerRow,
+      DngOpcode::ScalePerColumn(_) => DngOpcodeId::ScalePerColumn,
+      DngOpcode::WarpRectilinear2(_) => DngOpcodeId::WarpRectilinear2,
+    }
+  }
+
+  /// Serialize the variant-specific payload (everything after the opcode header).
+  fn encode_payload(&self, cur: &mut Cursor<Vec<u8>>) -> Result<()> {
+    match self {
+      DngOpcode::WarpRectilinear(op) => op.encode(cur),
+      DngOpcode::WarpFisheye(op) => op.encode(cur),
+      DngOpcode::FixVignetteRadial(op) => op.encode(cur),
+      DngOpcode::FixBadPixelsConstant(op) => op.encode(cur),
+      DngOpcode::FixBadPixelsList(op) => op.encode(cur),
+      DngOpcode::TrimBounds(op) => op.encode(cur),
+      DngOpcode::MapTable(op) => op.encode(cur),
+      DngOpcode::MapPolynomial(op) => op.encode(cur),
+      DngOpcode::GainMap(op) => op.encode(cur),
+      DngOpcode::DeltaPerRow(op) => op.encode(cur),
+      DngOpcode::DeltaPerColumn(op) => op.encode(cur),
+      DngOpcode::ScalePerRow(op) => op.encode(cur),
+      DngOpcode::ScalePerColumn(op) => op.encode(cur),
+      DngOpcode::WarpRectilinear2(op) => op.encode(cur),
+    }
+  }
+}
+
 pub fn decode_opcode_list(opcode_list: &[u8]) -> Result<Vec<DngOpcode>> {
   let mut cur = Cursor::new(opcode_list);
   let mut ops = Vec::new();
@@ -429,25 +881,31 @@ pub fn decode_opcode_list(opcode_list: &[u8]) -> Result<Vec<DngOpcode>> {
     let op_flags = cur.read_u32::<BigEndian>()?;
     let op_len = cur.read_u32::<BigEndian>()?;
     let pos_start = cur.position() as u32;
+    // End of this opcode's payload. Decoders check declared element counts
+    // against it before allocating; fixed-size fields are read without it, and
+    // an overrun there is caught by the size check after decoding.
+    // Clamp the budget to the bytes actually present: op_len is untrusted and a
+    // hostile value must never let a count exceed the real input length.
+    let limit = (pos_start as u64 + op_len as u64).min(opcode_list.len() as u64);
 
     match DngOpcodeId::try_from(op_id_code) {
       Ok(op_id) => {
         let flags = DngOpcodeFlags::decode(op_flags);
         let op = match op_id {
-          DngOpcodeId::WarpRectilinear => DngOpcode::WarpRectilinear(WarpRectilinear::decode(flags, &mut cur)?),
-          DngOpcodeId::WarpFisheye => DngOpcode::WarpFisheye(WarpFisheye::decode(flags, &mut cur)?),
-          DngOpcodeId::FixVignetteRadial => DngOpcode::FixVignetteRadial(FixVignetteRadial::decode(flags, &mut cur)?),
-          DngOpcodeId::FixBadPixelsConstant => DngOpcode::FixBadPixelsConstant(FixBadPixelsConstant::decode(flags, &mut cur)?),
-          DngOpcodeId::FixBadPixelsList => DngOpcode::FixBadPixelsList(FixBadPixelsList::decode(flags, &mut cur)?),
-          DngOpcodeId::TrimBounds => DngOpcode::TrimBounds(TrimBounds::decode(flags, &mut cur)?),
-          DngOpcodeId::MapTable => DngOpcode::MapTable(MapTable::decode(flags, &mut cur)?),
-          DngOpcodeId::MapPolynomial => DngOpcode::MapPolynomial(MapPolynomial::decode(flags, &mut cur)?),
-          DngOpcodeId::GainMap => DngOpcode::GainMap(GainMap::decode(flags, &mut cur)?),
-          DngOpcodeId::DeltaPerRow => DngOpcode::DeltaPerRow(ValuesPerRowOrCol::decode(flags, &mut cur)?),
-          DngOpcodeId::DeltaPerColumn => DngOpcode::DeltaPerColumn(ValuesPerRowOrCol::decode(flags, &mut cur)?),
-          DngOpcodeId::ScalePerRow => DngOpcode::ScalePerRow(ValuesPerRowOrCol::decode(flags, &mut cur)?),
-          DngOpcodeId::ScalePerColumn => DngOpcode::ScalePerColumn(ValuesPerRowOrCol::decode(flags, &mut cur)?),
-          DngOpcodeId::WarpRectilinear2 => DngOpcode::WarpRectilinear2(WarpRectilinear2::decode(flags, &mut cur)?),
+          DngOpcodeId::WarpRectilinear => DngOpcode::WarpRectilinear(WarpRectilinear::decode(flags, &mut cur, limit)?),
+          DngOpcodeId::WarpFisheye => DngOpcode::WarpFisheye(WarpFisheye::decode(flags, &mut cur, limit)?),
+          DngOpcodeId::FixVignetteRadial => DngOpcode::FixVignetteRadial(FixVignetteRadial::decode(flags, &mut cur, limit)?),
+          DngOpcodeId::FixBadPixelsConstant => DngOpcode::FixBadPixelsConstant(FixBadPixelsConstant::decode(flags, &mut cur, limit)?),
+          DngOpcodeId::FixBadPixelsList => DngOpcode::FixBadPixelsList(FixBadPixelsList::decode(flags, &mut cur, limit)?),
+          DngOpcodeId::TrimBounds => DngOpcode::TrimBounds(TrimBounds::decode(flags, &mut cur, limit)?),
+          DngOpcodeId::MapTable => DngOpcode::MapTable(MapTable::decode(flags, &mut cur, limit)?),
+          DngOpcodeId::MapPolynomial => DngOpcode::MapPolynomial(MapPolynomial::decode(flags, &mut cur, limit)?),
+          DngOpcodeId::GainMap => DngOpcode::GainMap(GainMap::decode(flags, &mut cur, limit)?),
+          DngOpcodeId::DeltaPerRow => DngOpcode::DeltaPerRow(ValuesPerRowOrCol::decode(flags, &mut cur, limit)?),
+          DngOpcodeId::DeltaPerColumn => DngOpcode::DeltaPerColumn(ValuesPerRowOrCol::decode(flags, &mut cur, limit)?),
+          DngOpcodeId::ScalePerRow => DngOpcode::ScalePerRow(ValuesPerRowOrCol::decode(flags, &mut cur, limit)?),
+          DngOpcodeId::ScalePerColumn => DngOpcode::ScalePerColumn(ValuesPerRowOrCol::decode(flags, &mut cur, limit)?),
+          DngOpcodeId::WarpRectilinear2 => DngOpcode::WarpRectilinear2(WarpRectilinear2::decode(flags, &mut cur, limit)?),
         };
         if pos_start + op_len != cur.position() as u32 {
           return Err(Error::new(ErrorKind::Other, "Invalid opcode size"));
@@ -463,3 +921,433 @@ pub fn decode_opcode_list(opcode_list: &[u8]) -> Result<Vec<DngOpcode>> {
 
   Ok(ops)
 }
+
+/// Serialize an opcode list back into a big-endian buffer, inverse of [`decode_opcode_list`].
+///
+/// The layout mirrors the decoder exactly: a `u32` count followed, for each
+/// opcode, by the id, spec version, packed flags, the payload length and the
+/// payload itself. The length is written as a placeholder and back-patched once
+/// the payload size is known, so the `pos_start + op_len` invariant the decoder
+/// checks holds on round-trip.
+pub fn encode_opcode_list(ops: &[DngOpcode]) -> Vec<u8> {
+  let mut cur = Cursor::new(Vec::new());
+  // Writing into an in-memory Vec is infallible, so the io errors can be unwrapped.
+  cur.write_u32::<BigEndian>(ops.len() as u32).unwrap();
+  for op in ops {
+    cur.write_u32::<BigEndian>(op.id() as u32).unwrap();
+    cur.write_u32::<BigEndian>(DNG_OPCODE_SPEC_VERSION).unwrap();
+    cur.write_u32::<BigEndian>(op.flags().encode()).unwrap();
+    let len_pos = cur.position();
+    cur.write_u32::<BigEndian>(0).unwrap(); // placeholder, back-patched below
+    let pos_start = cur.position();
+    op.encode_payload(&mut cur).unwrap();
+    let pos_end = cur.position();
+    cur.seek(SeekFrom::Start(len_pos)).unwrap();
+    cur.write_u32::<BigEndian>((pos_end - pos_start) as u32).unwrap();
+    cur.seek(SeekFrom::Start(pos_end)).unwrap();
+  }
+  cur.into_inner()
+}
+
+/// Distance from the warp center to the farthest image corner, in normalized coords.
+///
+/// Destination offsets are divided by this radius so that the warp polynomials
+/// operate on a unit-normalized radius regardless of the image aspect ratio.
+fn max_corner_radius(cx: f64, cy: f64) -> f64 {
+  [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)]
+    .iter()
+    .map(|(x, y)| ((x - cx).powi(2) + (y - cy).powi(2)).sqrt())
+    .fold(0.0, f64::max)
+}
+
+/// Bilinearly sample plane `plane` of `src` at fractional pixel coordinates, clamping at the edges.
+fn sample_bilinear(src: &[f32], dim: Dim2, plane_count: usize, plane: usize, fx: f64, fy: f64) -> f32 {
+  let x = fx.clamp(0.0, (dim.w - 1) as f64);
+  let y = fy.clamp(0.0, (dim.h - 1) as f64);
+  let x0 = x.floor() as usize;
+  let y0 = y.floor() as usize;
+  let x1 = (x0 + 1).min(dim.w - 1);
+  let y1 = (y0 + 1).min(dim.h - 1);
+  let tx = x - x0 as f64;
+  let ty = y - y0 as f64;
+  let at = |yy: usize, xx: usize| src[(yy * dim.w + xx) * plane_count + plane] as f64;
+  let top = at(y0, x0) * (1.0 - tx) + at(y0, x1) * tx;
+  let bottom = at(y1, x0) * (1.0 - tx) + at(y1, x1) * tx;
+  (top * (1.0 - ty) + bottom * ty) as f32
+}
+
+/// Resample every plane of `data` in place from a copy of itself.
+///
+/// `mapper` receives the plane index and the destination offset from the center
+/// (already divided by the max-corner radius) and returns the corresponding
+/// source offset in the same normalized space.
+fn warp_resample<F>(data: &mut [f32], dim: Dim2, plane_count: usize, cx: f64, cy: f64, mapper: F) -> Result<()>
+where
+  F: Fn(usize, f64, f64) -> (f64, f64),
+{
+  if dim.w == 0 || dim.h == 0 || plane_count == 0 {
+    return Ok(());
+  }
+  let rmax = max_corner_radius(cx, cy);
+  let src = data.to_vec();
+  for p in 0..plane_count {
+    for py in 0..dim.h {
+      let dy = ((py as f64 + 0.5) / dim.h as f64 - cy) / rmax;
+      for px in 0..dim.w {
+        let dx = ((px as f64 + 0.5) / dim.w as f64 - cx) / rmax;
+        let (sdx, sdy) = mapper(p, dx, dy);
+        let sx = (cx + sdx * rmax) * dim.w as f64 - 0.5;
+        let sy = (cy + sdy * rmax) * dim.h as f64 - 0.5;
+        data[(py * dim.w + px) * plane_count + p] = sample_bilinear(&src, dim, plane_count, p, sx, sy);
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Whether the CFA site at `(row, col)` carries a green sample for the given bayer phase.
+///
+/// Follows the DNG `BayerPhase` definition for the FixBadPixels opcodes:
+/// phase 0 = top-left green in a red row (`G R / B G`, GRBG),
+/// phase 1 = top-left green in a blue row (`G B / R G`, GBRG),
+/// phase 2 = top-left red (`R G / G B`, RGGB),
+/// phase 3 = top-left blue (`B G / G R`, BGGR).
+/// Green therefore sits on the even `(row + col)` checkerboard for phases 0/1
+/// (green at `(0, 0)`) and the odd one for phases 2/3 (green at `(0, 1)`).
+fn is_green_site(row: usize, col: usize, bayer_phase: u32) -> bool {
+  let even = (row + col) % 2 == 0;
+  match bayer_phase {
+    0 | 1 => even,
+    2 | 3 => !even,
+    _ => even,
+  }
+}
+
+/// Conceal a single defective CFA site by averaging its nearest same-color neighbors.
+///
+/// Green sites average their four diagonal neighbors; red/blue sites average the
+/// orthogonal neighbors two samples away, which are the nearest same-color pixels.
+fn conceal_point(data: &mut [f32], dim: Dim2, plane_count: usize, row: usize, col: usize, bayer_phase: u32) {
+  let offsets: [(isize, isize); 4] = if is_green_site(row, col, bayer_phase) {
+    [(-1, -1), (-1, 1), (1, -1), (1, 1)]
+  } else {
+    [(-2, 0), (2, 0), (0, -2), (0, 2)]
+  };
+  let mut sum = 0.0f32;
+  let mut count = 0u32;
+  for (dr, dc) in offsets {
+    let r = row as isize + dr;
+    let c = col as isize + dc;
+    if r >= 0 && c >= 0 && (r as usize) < dim.h && (c as usize) < dim.w {
+      sum += data[(r as usize * dim.w + c as usize) * plane_count];
+      count += 1;
+    }
+  }
+  if count > 0 {
+    data[(row * dim.w + col) * plane_count] = sum / count as f32;
+  }
+}
+
+/// Largest coordinate strictly before `edge` that shares `parity`, or `None` when it underflows.
+fn same_parity_before(edge: usize, parity: usize) -> Option<usize> {
+  if edge == 0 {
+    None
+  } else if (edge - 1) % 2 == parity {
+    Some(edge - 1)
+  } else if edge >= 2 {
+    Some(edge - 2)
+  } else {
+    None
+  }
+}
+
+/// Smallest coordinate at or after `edge` that shares `parity`, bounded by `limit`.
+fn same_parity_after(edge: usize, parity: usize, limit: usize) -> Option<usize> {
+  let c = if edge % 2 == parity { edge } else { edge + 1 };
+  if c < limit { Some(c) } else { None }
+}
+
+/// Conceal a defective rectangle by interpolating across it from the two bounding same-color lines.
+fn conceal_rect(data: &mut [f32], dim: Dim2, plane_count: usize, rect: &BadRect) {
+  let (top, left, bottom, right) = (rect.top as usize, rect.left as usize, rect.bottom as usize, rect.right as usize);
+  // Interpolate along the narrow axis of the defect (columns for tall rects, rows for wide ones).
+  let horizontal = (right - left) <= (bottom - top);
+  for row in top..bottom {
+    for col in left..right {
+      let idx = (row * dim.w + col) * plane_count;
+      let (lo, hi, pos) = if horizontal {
+        (same_parity_before(left, col % 2), same_parity_after(right, col % 2, dim.w), col)
+      } else {
+        (same_parity_before(top, row % 2), same_parity_after(bottom, row % 2, dim.h), row)
+      };
+      let sample = |line: usize| if horizontal { data[(row * dim.w + line) * plane_count] } else { data[(line * dim.w + col) * plane_count] };
+      match (lo, hi) {
+        (Some(a), Some(b)) => {
+          let t = (pos - a) as f32 / (b - a) as f32;
+          data[idx] = sample(a) * (1.0 - t) + sample(b) * t;
+        }
+        (Some(a), None) => data[idx] = sample(a),
+        (None, Some(b)) => data[idx] = sample(b),
+        (None, None) => {}
+      }
+    }
+  }
+}
+
+/// Apply a decoded opcode list to raw image data in list order.
+///
+/// `data` holds the samples of an image of dimension `dim` with interleaved
+/// planes; the number of planes is derived from the buffer length. Opcodes
+/// marked `optional` are skipped silently if they fail, while a failing
+/// mandatory opcode aborts the pass with an error.
+pub fn apply_opcodes(ops: &[DngOpcode], data: &mut [f32], dim: Dim2) -> Result<()> {
+  let pixels = dim.w * dim.h;
+  let plane_count = if pixels == 0 { 0 } else { data.len() / pixels };
+  for op in ops {
+    let res = match op {
+      DngOpcode::MapTable(op) => op.apply(data, dim, plane_count),
+      DngOpcode::MapPolynomial(op) => op.apply(data, dim, plane_count),
+      DngOpcode::DeltaPerRow(op) => op.apply(data, dim, plane_count, false, false),
+      DngOpcode::DeltaPerColumn(op) => op.apply(data, dim, plane_count, true, false),
+      DngOpcode::ScalePerRow(op) => op.apply(data, dim, plane_count, false, true),
+      DngOpcode::ScalePerColumn(op) => op.apply(data, dim, plane_count, true, true),
+      DngOpcode::GainMap(op) => op.apply(data, dim, plane_count),
+      DngOpcode::WarpRectilinear(op) => op.apply(data, dim, plane_count),
+      DngOpcode::WarpRectilinear2(op) => op.apply(data, dim, plane_count),
+      DngOpcode::WarpFisheye(op) => op.apply(data, dim, plane_count),
+      DngOpcode::FixBadPixelsConstant(op) => op.apply(data, dim, plane_count),
+      DngOpcode::FixBadPixelsList(op) => op.apply(data, dim, plane_count),
+      // Remaining opcodes are not yet applied by this pass.
+      _ => Ok(()),
+    };
+    if let Err(err) = res {
+      if !op.flags().optional {
+        return Err(err);
+      }
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn flags() -> DngOpcodeFlags {
+    DngOpcodeFlags { optional: true, preview_skip: false }
+  }
+
+  fn region() -> DngOpcodeRegion {
+    DngOpcodeRegion {
+      top: 0,
+      left: 0,
+      bottom: 4,
+      right: 4,
+      plane: 0,
+      planes: 1,
+      row_pitch: 1,
+      col_pitch: 1,
+    }
+  }
+
+  fn sample_ops() -> Vec<DngOpcode> {
+    vec![
+      DngOpcode::MapPolynomial(MapPolynomial {
+        flags: flags(),
+        region: region(),
+        coefs: vec![0.0, 0.5, 0.25],
+      }),
+      DngOpcode::GainMap(GainMap {
+        flags: DngOpcodeFlags { optional: false, preview_skip: true },
+        region: region(),
+        map_points_v: 2,
+        map_points_h: 2,
+        map_spacing_v: 0.5,
+        map_spacing_h: 0.5,
+        map_origin_v: 0.0,
+        map_origin_h: 0.0,
+        map_planes: 1,
+        map_gain: vec![1.0, 1.1, 1.2, 1.3],
+      }),
+      DngOpcode::ScalePerRow(ValuesPerRowOrCol {
+        flags: flags(),
+        region: region(),
+        values: vec![1.0, 0.9, 0.8, 0.7],
+      }),
+      DngOpcode::FixBadPixelsList(FixBadPixelsList {
+        flags: flags(),
+        bayer_phase: 1,
+        bad_points: vec![BadPoint { row: 1, column: 2 }],
+        bad_rects: vec![BadRect { top: 0, left: 0, bottom: 1, right: 4 }],
+      }),
+    ]
+  }
+
+  #[test]
+  fn encode_decode_roundtrip() {
+    let buf = encode_opcode_list(&sample_ops());
+    let ops = decode_opcode_list(&buf).unwrap();
+    let reencoded = encode_opcode_list(&ops);
+    assert_eq!(buf, reencoded);
+  }
+
+  #[test]
+  fn hostile_length_does_not_over_allocate() {
+    // A tiny opcode declaring op_len ~= u32::MAX and a ~1e9 element count must
+    // be rejected against the real buffer length, not trusted blindly.
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&1u32.to_be_bytes()); // opcode count
+    buf.extend_from_slice(&(DngOpcodeId::DeltaPerRow as u32).to_be_bytes());
+    buf.extend_from_slice(&0u32.to_be_bytes()); // spec version
+    buf.extend_from_slice(&0u32.to_be_bytes()); // flags
+    buf.extend_from_slice(&u32::MAX.to_be_bytes()); // hostile op_len
+    buf.extend_from_slice(&[0u8; 32]); // region (8 x u32)
+    buf.extend_from_slice(&0x3FFF_FFFFu32.to_be_bytes()); // hostile value count
+    assert!(decode_opcode_list(&buf).is_err());
+  }
+
+  #[test]
+  fn roundtrip_preserves_count_and_ids() {
+    let ops = sample_ops();
+    let decoded = decode_opcode_list(&encode_opcode_list(&ops)).unwrap();
+    assert_eq!(decoded.len(), ops.len());
+    for (a, b) in ops.iter().zip(decoded.iter()) {
+      assert_eq!(a.id() as u32, b.id() as u32);
+    }
+  }
+
+  const DIM: Dim2 = Dim2 { w: 4, h: 4 };
+
+  fn assert_close(a: f32, b: f32) {
+    assert!((a - b).abs() < 1e-5, "{a} != {b}");
+  }
+
+  #[test]
+  fn map_polynomial_evaluates_per_pixel() {
+    let mut data = vec![0.4f32; 16];
+    let op = DngOpcode::MapPolynomial(MapPolynomial {
+      flags: flags(),
+      region: region(),
+      coefs: vec![0.0, 0.5, 0.25],
+    });
+    apply_opcodes(&[op], &mut data, DIM).unwrap();
+    // 0.5 * 0.4 + 0.25 * 0.4^2 = 0.24
+    data.iter().for_each(|&v| assert_close(v, 0.24));
+  }
+
+  #[test]
+  fn map_table_looks_up_and_rescales() {
+    let mut data = vec![0.0, 0.5, 1.0, 0.5, 0.0, 0.5, 1.0, 0.5, 0.0, 0.5, 1.0, 0.5, 0.0, 0.5, 1.0, 0.5];
+    let op = DngOpcode::MapTable(MapTable {
+      flags: flags(),
+      region: region(),
+      table: vec![0, 32768, 65535],
+    });
+    apply_opcodes(&[op], &mut data, DIM).unwrap();
+    assert_close(data[0], 0.0);
+    assert_close(data[1], 32768.0 / 65535.0);
+    assert_close(data[2], 1.0);
+  }
+
+  #[test]
+  fn delta_per_row_adds_row_values() {
+    let mut data = vec![0.0f32; 16];
+    let op = DngOpcode::DeltaPerRow(ValuesPerRowOrCol {
+      flags: flags(),
+      region: region(),
+      values: vec![0.1, 0.2, 0.3, 0.4],
+    });
+    apply_opcodes(&[op], &mut data, DIM).unwrap();
+    for row in 0..4 {
+      for col in 0..4 {
+        assert_close(data[row * 4 + col], 0.1 * (row + 1) as f32);
+      }
+    }
+  }
+
+  #[test]
+  fn scale_per_column_multiplies_column_values() {
+    let mut data = vec![2.0f32; 16];
+    let op = DngOpcode::ScalePerColumn(ValuesPerRowOrCol {
+      flags: flags(),
+      region: region(),
+      values: vec![1.0, 2.0, 3.0, 4.0],
+    });
+    apply_opcodes(&[op], &mut data, DIM).unwrap();
+    for row in 0..4 {
+      for col in 0..4 {
+        assert_close(data[row * 4 + col], 2.0 * (col + 1) as f32);
+      }
+    }
+  }
+
+  #[test]
+  fn gain_map_bilinearly_interpolates() {
+    let mut data = vec![1.0f32; 16];
+    let op = DngOpcode::GainMap(GainMap {
+      flags: flags(),
+      region: region(),
+      map_points_v: 2,
+      map_points_h: 2,
+      map_spacing_v: 0.5,
+      map_spacing_h: 0.5,
+      map_origin_v: 0.0,
+      map_origin_h: 0.0,
+      map_planes: 1,
+      map_gain: vec![1.0, 2.0, 3.0, 4.0],
+    });
+    apply_opcodes(&[op], &mut data, DIM).unwrap();
+    // x=0,y=0 -> grid corner (0,0) gain 1.0
+    assert_close(data[0], 1.0);
+    // x=1,y=0 -> u=0.25, gx=0.5 -> 1.0*0.5 + 2.0*0.5 = 1.5
+    assert_close(data[1], 1.5);
+  }
+
+  #[test]
+  fn is_green_site_matches_dng_bayer_phases() {
+    // Phase 0 = GRBG (G R / B G): green on the even (row+col) checkerboard.
+    assert!(is_green_site(0, 0, 0)); // G
+    assert!(!is_green_site(0, 1, 0)); // R
+    assert!(!is_green_site(1, 0, 0)); // B
+    assert!(is_green_site(1, 1, 0)); // G
+    // Phase 2 = RGGB (R G / G B): green on the odd (row+col) checkerboard.
+    assert!(!is_green_site(0, 0, 2)); // R
+    assert!(is_green_site(0, 1, 2)); // G
+    assert!(is_green_site(1, 0, 2)); // G
+    assert!(!is_green_site(1, 1, 2)); // B
+  }
+
+  #[test]
+  fn bad_point_is_filled_from_same_color_neighbors() {
+    // Phase 2 (RGGB, top-left red) puts green on the odd (row+col) checkerboard,
+    // so (1,2) is a green site and its four diagonal neighbors are also green.
+    let mut data = vec![10.0f32; 16]; // red/blue sites marked with an off value
+    data[1] = 0.6; // (0,1) green
+    data[3] = 0.7; // (0,3) green
+    data[9] = 0.8; // (2,1) green
+    data[11] = 0.9; // (2,3) green
+    let op = DngOpcode::FixBadPixelsList(FixBadPixelsList {
+      flags: flags(),
+      bayer_phase: 2,
+      bad_points: vec![BadPoint { row: 1, column: 2 }],
+      bad_rects: vec![],
+    });
+    apply_opcodes(&[op], &mut data, DIM).unwrap();
+    // Average of the four green diagonals, not the red/blue orthogonals.
+    assert_close(data[6], 0.75);
+  }
+
+  #[test]
+  fn optional_opcode_failure_leaves_data_untouched() {
+    let mut data = vec![1.0f32; 16];
+    // Too few per-row values: the opcode must fail, and being optional it is
+    // skipped without having modified any pixel.
+    let op = DngOpcode::DeltaPerRow(ValuesPerRowOrCol {
+      flags: DngOpcodeFlags { optional: true, preview_skip: false },
+      region: region(),
+      values: vec![0.1, 0.2],
+    });
+    apply_opcodes(&[op], &mut data, DIM).unwrap();
+    data.iter().for_each(|&v| assert_close(v, 1.0));
+  }
+}